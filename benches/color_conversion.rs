@@ -0,0 +1,59 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use scap::frame::{convert_bgra_to_rgb, remove_alpha_channel};
+
+// Plain scalar baselines, kept deliberately un-multiversioned so the
+// benchmark has something to compare the dispatched kernels against.
+fn scalar_convert_bgra_to_rgb(data: &[u8]) -> Vec<u8> {
+    let mut rgb_data = Vec::with_capacity(data.len() / 4 * 3);
+    for chunk in data.chunks_exact(4) {
+        rgb_data.push(chunk[2]);
+        rgb_data.push(chunk[1]);
+        rgb_data.push(chunk[0]);
+    }
+
+    rgb_data
+}
+
+fn scalar_remove_alpha_channel(data: &[u8]) -> Vec<u8> {
+    let mut bgr_data = Vec::with_capacity(data.len() / 4 * 3);
+    for chunk in data.chunks_exact(4) {
+        bgr_data.push(chunk[0]);
+        bgr_data.push(chunk[1]);
+        bgr_data.push(chunk[2]);
+    }
+
+    bgr_data
+}
+
+fn bench_convert_bgra_to_rgb(c: &mut Criterion) {
+    let data = vec![0u8; 1920 * 1080 * 4];
+
+    let mut group = c.benchmark_group("convert_bgra_to_rgb");
+    group.bench_function("scalar", |b| {
+        b.iter(|| scalar_convert_bgra_to_rgb(black_box(&data)))
+    });
+    group.bench_function("dispatched", |b| {
+        b.iter(|| convert_bgra_to_rgb(black_box(data.clone())))
+    });
+    group.finish();
+}
+
+fn bench_remove_alpha_channel(c: &mut Criterion) {
+    let data = vec![0u8; 1920 * 1080 * 4];
+
+    let mut group = c.benchmark_group("remove_alpha_channel");
+    group.bench_function("scalar", |b| {
+        b.iter(|| scalar_remove_alpha_channel(black_box(&data)))
+    });
+    group.bench_function("dispatched", |b| {
+        b.iter(|| remove_alpha_channel(black_box(data.clone())))
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_convert_bgra_to_rgb,
+    bench_remove_alpha_channel
+);
+criterion_main!(benches);
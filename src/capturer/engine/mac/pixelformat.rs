@@ -8,13 +8,16 @@ use super::{
     pixel_buffer::{pixel_buffer_bounds, sample_buffer_to_pixel_buffer},
 };
 use crate::frame::{
-    convert_bgra_to_rgb, get_cropped_data, remove_alpha_channel, BGRAFrame, BGRFrame, RGBFrame,
-    YUVFrame,
+    align_and_clamp_crop, convert_bgra_to_rgb, deinterleave_chroma, remove_alpha_channel,
+    AudioFrame, BGRAFrame, BGRFrame, CropRect, PlanarYUVFrame, Range, RGBFrame, YUVFrame,
 };
 use core_graphics_helmer_fork::display::{CFArrayGetCount, CFArrayGetValueAtIndex, CFArrayRef};
 use core_video_sys::{
-    CVPixelBufferGetBaseAddress, CVPixelBufferGetBaseAddressOfPlane, CVPixelBufferGetBytesPerRow,
-    CVPixelBufferGetBytesPerRowOfPlane, CVPixelBufferLockBaseAddress,
+    kCVPixelFormatType_420YpCbCr8BiPlanarFullRange, kCVPixelFormatType_420YpCbCr8BiPlanarVideoRange,
+    kCVPixelFormatType_32BGRA, CVBufferRelease, CVBufferRetain, CVPixelBufferGetBaseAddress,
+    CVPixelBufferGetBaseAddressOfPlane, CVPixelBufferGetBytesPerRow,
+    CVPixelBufferGetBytesPerRowOfPlane, CVPixelBufferGetHeight, CVPixelBufferGetPixelFormatType,
+    CVPixelBufferGetWidth, CVPixelBufferLockBaseAddress, CVPixelBufferRef,
     CVPixelBufferUnlockBaseAddress,
 };
 
@@ -28,7 +31,10 @@ pub fn get_pts_in_nanoseconds(sample_buffer: &CMSampleBuffer) -> u64 {
     (seconds * 1_000_000_000.).trunc() as u64
 }
 
-pub unsafe fn create_yuv_frame(sample_buffer: CMSampleBuffer) -> Option<YUVFrame> {
+pub unsafe fn create_yuv_frame(
+    sample_buffer: CMSampleBuffer,
+    crop: Option<CropRect>,
+) -> Option<YUVFrame> {
     // Check that the frame status is complete
     let buffer_ref = &(*sample_buffer.sys_ref);
     {
@@ -61,6 +67,10 @@ pub unsafe fn create_yuv_frame(sample_buffer: CMSampleBuffer) -> Option<YUVFrame
     let display_time = get_pts_in_nanoseconds(&sample_buffer);
     let pixel_buffer = sample_buffer_to_pixel_buffer(&sample_buffer);
 
+    // Reject anything that isn't 420 biplanar (e.g. 422) instead of reading
+    // out of bounds assuming a chroma layout that isn't actually there.
+    let color_range = detect_yuv_color_range(pixel_buffer)?;
+
     CVPixelBufferLockBaseAddress(pixel_buffer, 0);
 
     let (width, height) = pixel_buffer_bounds(pixel_buffer);
@@ -68,40 +78,121 @@ pub unsafe fn create_yuv_frame(sample_buffer: CMSampleBuffer) -> Option<YUVFrame
         return None;
     }
 
-    let luminance_bytes_address = CVPixelBufferGetBaseAddressOfPlane(pixel_buffer, 0);
+    let region = resolve_crop(crop, pixel_buffer, width, height);
+
+    let luminance_bytes_address = CVPixelBufferGetBaseAddressOfPlane(pixel_buffer, 0) as *mut u8;
     let luminance_stride = CVPixelBufferGetBytesPerRowOfPlane(pixel_buffer, 0);
-    let luminance_bytes = slice::from_raw_parts(
-        luminance_bytes_address as *mut u8,
-        height * luminance_stride,
-    )
-    .to_vec();
+    let mut luminance_bytes = Vec::with_capacity(region.width as usize * region.height as usize);
+    for row in 0..region.height as usize {
+        let row_start = luminance_bytes_address
+            .wrapping_add((region.y as usize + row) * luminance_stride)
+            .wrapping_add(region.x as usize);
+        luminance_bytes
+            .extend_from_slice(slice::from_raw_parts(row_start, region.width as usize));
+    }
+
+    // Chroma is subsampled 2x in both dimensions, so the crop rect is halved too.
+    let chroma_x = region.x / 2;
+    let chroma_y = region.y / 2;
+    let chroma_width = region.width / 2;
+    let chroma_height = region.height / 2;
 
-    let chrominance_bytes_address = CVPixelBufferGetBaseAddressOfPlane(pixel_buffer, 1);
+    let chrominance_bytes_address =
+        CVPixelBufferGetBaseAddressOfPlane(pixel_buffer, 1) as *mut u8;
     let chrominance_stride = CVPixelBufferGetBytesPerRowOfPlane(pixel_buffer, 1);
-    let chrominance_bytes = slice::from_raw_parts(
-        chrominance_bytes_address as *mut u8,
-        height * chrominance_stride / 2,
-    )
-    .to_vec();
+    let mut chrominance_bytes = Vec::with_capacity(chroma_width as usize * 2 * chroma_height as usize);
+    for row in 0..chroma_height as usize {
+        let row_start = chrominance_bytes_address
+            .wrapping_add((chroma_y as usize + row) * chrominance_stride)
+            .wrapping_add(chroma_x as usize * 2);
+        chrominance_bytes
+            .extend_from_slice(slice::from_raw_parts(row_start, chroma_width as usize * 2));
+    }
 
     CVPixelBufferUnlockBaseAddress(pixel_buffer, 0);
 
     YUVFrame {
         display_time,
-        width: width as i32,
-        height: height as i32,
+        width: region.width,
+        height: region.height,
         luminance_bytes,
-        luminance_stride: luminance_stride as i32,
+        luminance_stride: region.width,
         chrominance_bytes,
-        chrominance_stride: chrominance_stride as i32,
+        chrominance_stride: chroma_width * 2,
+        color_range,
     }
     .into()
 }
 
-pub unsafe fn create_bgr_frame(sample_buffer: CMSampleBuffer) -> Option<BGRFrame> {
+// Probes the pixel buffer's format and returns the YUV range it encodes, or
+// `None` for anything other than 420 biplanar video/full range (e.g. 422),
+// which this module does not know how to read.
+unsafe fn detect_yuv_color_range(pixel_buffer: CVPixelBufferRef) -> Option<Range> {
+    match CVPixelBufferGetPixelFormatType(pixel_buffer) {
+        kCVPixelFormatType_420YpCbCr8BiPlanarVideoRange => Some(Range::Video),
+        kCVPixelFormatType_420YpCbCr8BiPlanarFullRange => Some(Range::Full),
+        _ => None,
+    }
+}
+
+// Produces a fully planar I420 frame by de-interleaving the NV12
+// chrominance plane into separate, tightly-packed U and V planes.
+pub unsafe fn create_i420_frame(
+    sample_buffer: CMSampleBuffer,
+    crop: Option<CropRect>,
+) -> Option<PlanarYUVFrame> {
+    let yuv_frame = create_yuv_frame(sample_buffer, crop)?;
+    let (u, v) = deinterleave_chroma(&yuv_frame.chrominance_bytes, yuv_frame.chrominance_stride);
+
+    Some(PlanarYUVFrame {
+        display_time: yuv_frame.display_time,
+        width: yuv_frame.width,
+        height: yuv_frame.height,
+        y: yuv_frame.luminance_bytes,
+        u,
+        v,
+        y_stride: yuv_frame.luminance_stride,
+        u_stride: yuv_frame.chrominance_stride / 2,
+        v_stride: yuv_frame.chrominance_stride / 2,
+        color_range: yuv_frame.color_range,
+    })
+}
+
+// Same as `create_i420_frame`, but with the U and V planes swapped (YV12).
+pub unsafe fn create_yv12_frame(
+    sample_buffer: CMSampleBuffer,
+    crop: Option<CropRect>,
+) -> Option<PlanarYUVFrame> {
+    let yuv_frame = create_yuv_frame(sample_buffer, crop)?;
+    let (u, v) = deinterleave_chroma(&yuv_frame.chrominance_bytes, yuv_frame.chrominance_stride);
+
+    Some(PlanarYUVFrame {
+        display_time: yuv_frame.display_time,
+        width: yuv_frame.width,
+        height: yuv_frame.height,
+        y: yuv_frame.luminance_bytes,
+        u: v,
+        v: u,
+        y_stride: yuv_frame.luminance_stride,
+        u_stride: yuv_frame.chrominance_stride / 2,
+        v_stride: yuv_frame.chrominance_stride / 2,
+        color_range: yuv_frame.color_range,
+    })
+}
+
+pub unsafe fn create_bgr_frame(
+    sample_buffer: CMSampleBuffer,
+    crop: Option<CropRect>,
+) -> Option<BGRFrame> {
     let pixel_buffer = sample_buffer_to_pixel_buffer(&sample_buffer);
     let display_time = get_pts_in_nanoseconds(&sample_buffer);
 
+    // Bail instead of assuming 4 bytes per pixel if the stream negotiated a
+    // format other than 32-bit BGRA.
+    if CVPixelBufferGetPixelFormatType(pixel_buffer) != kCVPixelFormatType_32BGRA {
+        return None;
+    }
+
     CVPixelBufferLockBaseAddress(pixel_buffer, 0);
 
     let (width, height) = pixel_buffer_bounds(pixel_buffer);
@@ -112,29 +203,46 @@ pub unsafe fn create_bgr_frame(sample_buffer: CMSampleBuffer) -> Option<BGRFrame
     let base_address = CVPixelBufferGetBaseAddress(pixel_buffer);
     let bytes_per_row = CVPixelBufferGetBytesPerRow(pixel_buffer);
 
-    let data = slice::from_raw_parts(base_address as *mut u8, bytes_per_row * height).to_vec();
+    let region = resolve_crop(crop, pixel_buffer, width, height);
 
-    let cropped_data = get_cropped_data(
-        data,
-        (bytes_per_row / 4) as i32,
-        height as i32,
-        width as i32,
-    );
+    // Read each row directly out of the live base address rather than
+    // pre-copying the whole buffer: `region` is clamped against
+    // `CVPixelBufferGetWidth`/`GetHeight`, which can disagree with
+    // `pixel_buffer_bounds` (see
+    // https://stackoverflow.com/questions/57904574), so a buffer sized off
+    // `pixel_buffer_bounds` could be too small for the region and panic.
+    let mut data: Vec<u8> = vec![];
+
+    for i in 0..region.height as usize {
+        let start = (base_address as *mut u8)
+            .wrapping_add((region.y as usize + i) * bytes_per_row)
+            .wrapping_add(region.x as usize * 4);
+        data.extend_from_slice(slice::from_raw_parts(start, 4 * region.width as usize));
+    }
 
     CVPixelBufferUnlockBaseAddress(pixel_buffer, 0);
 
     Some(BGRFrame {
         display_time,
-        width: width as i32, // width does not give accurate results - https://stackoverflow.com/questions/19587185/cvpixelbuffergetbytesperrow-for-cvimagebufferref-returns-unexpected-wrong-valu
-        height: height as i32,
-        data: remove_alpha_channel(cropped_data),
+        width: region.width,
+        height: region.height,
+        data: remove_alpha_channel(data),
     })
 }
 
-pub unsafe fn create_bgra_frame(sample_buffer: CMSampleBuffer) -> Option<BGRAFrame> {
+pub unsafe fn create_bgra_frame(
+    sample_buffer: CMSampleBuffer,
+    crop: Option<CropRect>,
+) -> Option<BGRAFrame> {
     let pixel_buffer = sample_buffer_to_pixel_buffer(&sample_buffer);
     let display_time = get_pts_in_nanoseconds(&sample_buffer);
 
+    // Bail instead of assuming 4 bytes per pixel if the stream negotiated a
+    // format other than 32-bit BGRA.
+    if CVPixelBufferGetPixelFormatType(pixel_buffer) != kCVPixelFormatType_32BGRA {
+        return None;
+    }
+
     CVPixelBufferLockBaseAddress(pixel_buffer, 0);
 
     let (width, height) = pixel_buffer_bounds(pixel_buffer);
@@ -145,27 +253,40 @@ pub unsafe fn create_bgra_frame(sample_buffer: CMSampleBuffer) -> Option<BGRAFra
     let base_address = CVPixelBufferGetBaseAddress(pixel_buffer);
     let bytes_per_row = CVPixelBufferGetBytesPerRow(pixel_buffer);
 
+    let region = resolve_crop(crop, pixel_buffer, width, height);
+
     let mut data: Vec<u8> = vec![];
 
-    for i in 0..height {
-        let start = (base_address as *mut u8).wrapping_add(i * bytes_per_row);
-        data.extend_from_slice(slice::from_raw_parts(start, 4 * width));
+    for i in 0..region.height as usize {
+        let start = (base_address as *mut u8)
+            .wrapping_add((region.y as usize + i) * bytes_per_row)
+            .wrapping_add(region.x as usize * 4);
+        data.extend_from_slice(slice::from_raw_parts(start, 4 * region.width as usize));
     }
 
     CVPixelBufferUnlockBaseAddress(pixel_buffer, 0);
 
     Some(BGRAFrame {
         display_time,
-        width: width as i32, // width does not give accurate results - https://stackoverflow.com/questions/19587185/cvpixelbuffergetbytesperrow-for-cvimagebufferref-returns-unexpected-wrong-valu
-        height: height as i32,
+        width: region.width,
+        height: region.height,
         data,
     })
 }
 
-pub unsafe fn create_rgb_frame(sample_buffer: CMSampleBuffer) -> Option<RGBFrame> {
+pub unsafe fn create_rgb_frame(
+    sample_buffer: CMSampleBuffer,
+    crop: Option<CropRect>,
+) -> Option<RGBFrame> {
     let pixel_buffer = sample_buffer_to_pixel_buffer(&sample_buffer);
     let display_time = get_pts_in_nanoseconds(&sample_buffer);
 
+    // Bail instead of assuming 4 bytes per pixel if the stream negotiated a
+    // format other than 32-bit BGRA.
+    if CVPixelBufferGetPixelFormatType(pixel_buffer) != kCVPixelFormatType_32BGRA {
+        return None;
+    }
+
     CVPixelBufferLockBaseAddress(pixel_buffer, 0);
 
     let (width, height) = pixel_buffer_bounds(pixel_buffer);
@@ -176,21 +297,258 @@ pub unsafe fn create_rgb_frame(sample_buffer: CMSampleBuffer) -> Option<RGBFrame
     let base_address = CVPixelBufferGetBaseAddress(pixel_buffer);
     let bytes_per_row = CVPixelBufferGetBytesPerRow(pixel_buffer);
 
-    let data = slice::from_raw_parts(base_address as *mut u8, bytes_per_row * height).to_vec();
+    let region = resolve_crop(crop, pixel_buffer, width, height);
 
-    let cropped_data = get_cropped_data(
-        data,
-        (bytes_per_row / 4) as i32,
-        height as i32,
-        width as i32,
-    );
+    // Read each row directly out of the live base address rather than
+    // pre-copying the whole buffer: `region` is clamped against
+    // `CVPixelBufferGetWidth`/`GetHeight`, which can disagree with
+    // `pixel_buffer_bounds` (see
+    // https://stackoverflow.com/questions/57904574), so a buffer sized off
+    // `pixel_buffer_bounds` could be too small for the region and panic.
+    let mut data: Vec<u8> = vec![];
+
+    for i in 0..region.height as usize {
+        let start = (base_address as *mut u8)
+            .wrapping_add((region.y as usize + i) * bytes_per_row)
+            .wrapping_add(region.x as usize * 4);
+        data.extend_from_slice(slice::from_raw_parts(start, 4 * region.width as usize));
+    }
 
     CVPixelBufferUnlockBaseAddress(pixel_buffer, 0);
 
     Some(RGBFrame {
         display_time,
-        width: width as i32, // width does not give accurate results - https://stackoverflow.com/questions/19587185/cvpixelbuffergetbytesperrow-for-cvimagebufferref-returns-unexpected-wrong-valu
+        width: region.width,
+        height: region.height,
+        data: convert_bgra_to_rgb(data),
+    })
+}
+
+// Resolves the caller's optional crop request against the pixel buffer's real
+// dimensions, defaulting to the full (reported) frame when no crop is given.
+unsafe fn resolve_crop(
+    crop: Option<CropRect>,
+    pixel_buffer: CVPixelBufferRef,
+    width: usize,
+    height: usize,
+) -> CropRect {
+    crop.map(|crop| {
+        align_and_clamp_crop(
+            crop,
+            CVPixelBufferGetWidth(pixel_buffer) as i32,
+            CVPixelBufferGetHeight(pixel_buffer) as i32,
+        )
+    })
+    .unwrap_or(CropRect {
+        x: 0,
+        y: 0,
+        width: width as i32,
         height: height as i32,
-        data: convert_bgra_to_rgb(cropped_data),
+    })
+}
+
+// Pulls PCM samples out of an audio `CMSampleBuffer` from the stream's audio output,
+// reusing the same presentation clock as the video frames so the two can be muxed.
+pub unsafe fn create_audio_frame(sample_buffer: CMSampleBuffer) -> Option<AudioFrame> {
+    let pts = get_pts_in_nanoseconds(&sample_buffer);
+
+    let buffer_ref = &(*sample_buffer.sys_ref);
+    let block_buffer = CMSampleBufferGetDataBuffer(buffer_ref);
+    if block_buffer.is_null() {
+        return None;
+    }
+
+    let format_description = CMSampleBufferGetFormatDescription(buffer_ref);
+    if format_description.is_null() {
+        return None;
+    }
+    let asbd = CMAudioFormatDescriptionGetStreamBasicDescription(format_description);
+    if asbd.is_null() {
+        return None;
+    }
+    let asbd = &*asbd;
+
+    // `CMBlockBufferGetDataPointer` only guarantees a contiguous run starting
+    // at the requested offset, not the whole buffer — audio block buffers are
+    // frequently segmented. Use `CMBlockBufferCopyDataBytes` instead, which
+    // flattens every segment into our destination buffer regardless.
+    let total_length = CMBlockBufferGetDataLength(block_buffer);
+    let mut samples = vec![0u8; total_length];
+    let status = CMBlockBufferCopyDataBytes(
+        block_buffer,
+        0,
+        total_length,
+        samples.as_mut_ptr() as *mut std::ffi::c_void,
+    );
+    if status != 0 {
+        return None;
+    }
+
+    Some(AudioFrame {
+        pts,
+        sample_rate: asbd.mSampleRate as u32,
+        channels: asbd.mChannelsPerFrame,
+        bit_depth: asbd.mBitsPerChannel,
+        interleaved: asbd.mFormatFlags & kAudioFormatFlagIsNonInterleaved == 0,
+        samples,
+    })
+}
+
+// Retains a `CVPixelBuffer` instead of copying it, so a consumer that encodes
+// or uploads to a GPU texture immediately can read straight from the
+// IOSurface-backed memory. The buffer is released when the frame is dropped.
+pub struct RetainedFrame {
+    display_time: u64,
+    pixel_buffer: CVPixelBufferRef,
+}
+
+impl RetainedFrame {
+    pub fn display_time(&self) -> u64 {
+        self.display_time
+    }
+
+    pub fn width(&self) -> i32 {
+        unsafe { CVPixelBufferGetWidth(self.pixel_buffer) as i32 }
+    }
+
+    pub fn height(&self) -> i32 {
+        unsafe { CVPixelBufferGetHeight(self.pixel_buffer) as i32 }
+    }
+
+    // Locks the underlying pixel buffer and hands back an accessor for its
+    // plane pointers/strides, valid for the lifetime of the returned borrow.
+    pub fn lock(&self) -> LockedPlanes<'_> {
+        unsafe { CVPixelBufferLockBaseAddress(self.pixel_buffer, 0) };
+        LockedPlanes { frame: self }
+    }
+
+    // Copies this frame into an owned `BGRAFrame`, for consumers that do need
+    // a copy (e.g. to hold onto the data past the next captured frame).
+    pub fn materialize_bgra(&self) -> Option<BGRAFrame> {
+        // Bail instead of assuming 4 bytes per pixel if the stream negotiated a
+        // format other than 32-bit BGRA.
+        if unsafe { CVPixelBufferGetPixelFormatType(self.pixel_buffer) } != kCVPixelFormatType_32BGRA
+        {
+            return None;
+        }
+
+        let locked = self.lock();
+        let (base_address, bytes_per_row) = locked.base();
+        let width = self.width();
+        let height = self.height();
+        if width == 0 || height == 0 {
+            return None;
+        }
+
+        let mut data: Vec<u8> = vec![];
+        for i in 0..height as usize {
+            let start = base_address.wrapping_add(i * bytes_per_row);
+            data.extend_from_slice(unsafe { slice::from_raw_parts(start, 4 * width as usize) });
+        }
+
+        Some(BGRAFrame {
+            display_time: self.display_time,
+            width,
+            height,
+            data,
+        })
+    }
+
+    // Copies this frame into an owned `YUVFrame`, for consumers that do need
+    // a copy (e.g. to hold onto the data past the next captured frame).
+    pub fn materialize_yuv(&self) -> Option<YUVFrame> {
+        let color_range = unsafe { detect_yuv_color_range(self.pixel_buffer) }?;
+        let locked = self.lock();
+
+        let (luminance_bytes_address, luminance_stride) = locked.plane(0);
+        let (chrominance_bytes_address, chrominance_stride) = locked.plane(1);
+
+        let width = self.width();
+        let height = self.height();
+        if width == 0 || height == 0 {
+            return None;
+        }
+
+        let luminance_bytes = unsafe {
+            slice::from_raw_parts(luminance_bytes_address, height as usize * luminance_stride)
+                .to_vec()
+        };
+        let chrominance_bytes = unsafe {
+            slice::from_raw_parts(
+                chrominance_bytes_address,
+                height as usize * chrominance_stride / 2,
+            )
+            .to_vec()
+        };
+
+        Some(YUVFrame {
+            display_time: self.display_time,
+            width,
+            height,
+            luminance_bytes,
+            luminance_stride: luminance_stride as i32,
+            chrominance_bytes,
+            chrominance_stride: chrominance_stride as i32,
+            color_range,
+        })
+    }
+}
+
+impl Drop for RetainedFrame {
+    fn drop(&mut self) {
+        unsafe { CVBufferRelease(self.pixel_buffer) };
+    }
+}
+
+// Holds the pixel buffer's base-address lock for as long as this borrow of
+// its `RetainedFrame` is alive, unlocking it on drop.
+pub struct LockedPlanes<'a> {
+    frame: &'a RetainedFrame,
+}
+
+impl LockedPlanes<'_> {
+    // Returns the locked base address and bytes-per-row of a non-planar
+    // pixel buffer (e.g. 32-bit BGRA).
+    pub fn base(&self) -> (*const u8, usize) {
+        unsafe {
+            (
+                CVPixelBufferGetBaseAddress(self.frame.pixel_buffer) as *const u8,
+                CVPixelBufferGetBytesPerRow(self.frame.pixel_buffer),
+            )
+        }
+    }
+
+    // Returns the locked base address and bytes-per-row for the given plane
+    // of a biplanar pixel buffer (0 for luminance, 1 for chrominance).
+    pub fn plane(&self, index: usize) -> (*const u8, usize) {
+        unsafe {
+            (
+                CVPixelBufferGetBaseAddressOfPlane(self.frame.pixel_buffer, index) as *const u8,
+                CVPixelBufferGetBytesPerRowOfPlane(self.frame.pixel_buffer, index),
+            )
+        }
+    }
+}
+
+impl Drop for LockedPlanes<'_> {
+    fn drop(&mut self) {
+        unsafe { CVPixelBufferUnlockBaseAddress(self.frame.pixel_buffer, 0) };
+    }
+}
+
+// Retains the sample buffer's pixel buffer so its memory can be read without
+// an upfront copy; release happens when the returned `RetainedFrame` drops.
+pub unsafe fn create_retained_frame(sample_buffer: CMSampleBuffer) -> Option<RetainedFrame> {
+    let display_time = get_pts_in_nanoseconds(&sample_buffer);
+    let pixel_buffer = sample_buffer_to_pixel_buffer(&sample_buffer);
+    if pixel_buffer.is_null() {
+        return None;
+    }
+
+    CVBufferRetain(pixel_buffer);
+
+    Some(RetainedFrame {
+        display_time,
+        pixel_buffer,
     })
 }
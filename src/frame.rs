@@ -0,0 +1,171 @@
+use multiversion::multiversion;
+
+#[derive(Debug, Clone)]
+pub struct BGRAFrame {
+    pub display_time: u64,
+    pub width: i32,
+    pub height: i32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BGRFrame {
+    pub display_time: u64,
+    pub width: i32,
+    pub height: i32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RGBFrame {
+    pub display_time: u64,
+    pub width: i32,
+    pub height: i32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct YUVFrame {
+    pub display_time: u64,
+    pub width: i32,
+    pub height: i32,
+    pub luminance_bytes: Vec<u8>,
+    pub luminance_stride: i32,
+    pub chrominance_bytes: Vec<u8>,
+    pub chrominance_stride: i32,
+    pub color_range: Range,
+}
+
+/// Whether a YUV buffer's luma/chroma samples span the full 0–255 byte range
+/// or the video-range 16–235/16–240 subset, which changes how downstream
+/// color conversion must scale Y before it is usable as RGB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Range {
+    Video,
+    Full,
+}
+
+/// A fully planar YUV 4:2:0 frame, with the chrominance plane de-interleaved
+/// into tightly-packed U and V planes (I420) or V and U planes (YV12).
+#[derive(Debug, Clone)]
+pub struct PlanarYUVFrame {
+    pub display_time: u64,
+    pub width: i32,
+    pub height: i32,
+    pub y: Vec<u8>,
+    pub u: Vec<u8>,
+    pub v: Vec<u8>,
+    pub y_stride: i32,
+    pub u_stride: i32,
+    pub v_stride: i32,
+    pub color_range: Range,
+}
+
+/// A PCM audio frame pulled from a ScreenCaptureKit audio `CMSampleBuffer`.
+///
+/// `samples` holds the raw bytes copied out of the sample buffer's block
+/// buffer, laid out according to `channels`/`bit_depth`/`interleaved` as
+/// reported by the stream's `AudioStreamBasicDescription`.
+#[derive(Debug, Clone)]
+pub struct AudioFrame {
+    pub pts: u64,
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub bit_depth: u32,
+    pub interleaved: bool,
+    pub samples: Vec<u8>,
+}
+
+/// A sub-region of a pixel buffer, in pixel coordinates relative to its
+/// top-left corner.
+#[derive(Debug, Clone, Copy)]
+pub struct CropRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+// Snaps a crop rect's origin and extent down to even pixels (required for
+// 4:2:0 chroma subsampling, where the chroma plane is computed as
+// `width / 2`/`height / 2`) and clamps it to the pixel buffer's real
+// dimensions, which can differ from a frame's reported width/height due to
+// stride padding.
+pub fn align_and_clamp_crop(crop: CropRect, buffer_width: i32, buffer_height: i32) -> CropRect {
+    let x = (crop.x & !1).clamp(0, buffer_width.max(0));
+    let y = (crop.y & !1).clamp(0, buffer_height.max(0));
+
+    // Round down to even after clamping so it still fits within the buffer.
+    let width = (crop.width.min(buffer_width - x).max(0)) & !1;
+    let height = (crop.height.min(buffer_height - y).max(0)) & !1;
+
+    CropRect {
+        x,
+        y,
+        width,
+        height,
+    }
+}
+
+// Splits a tightly-packed interleaved CbCr plane (as produced for a
+// biplanar NV12 `YUVFrame`) into separate U and V planes, each at half the
+// chrominance plane's stride. Swap the returned tuple to get YV12 instead
+// of I420.
+pub fn deinterleave_chroma(chrominance_bytes: &[u8], chrominance_stride: i32) -> (Vec<u8>, Vec<u8>) {
+    let stride = chrominance_stride as usize;
+
+    let mut u = Vec::with_capacity(chrominance_bytes.len() / 2);
+    let mut v = Vec::with_capacity(chrominance_bytes.len() / 2);
+
+    for row in chrominance_bytes.chunks_exact(stride) {
+        for pair in row.chunks_exact(2) {
+            u.push(pair[0]);
+            v.push(pair[1]);
+        }
+    }
+
+    (u, v)
+}
+
+// Converts tightly-packed BGRA to RGB, dropping the alpha channel and
+// swapping the byte order. Compiled once per target feature set below and
+// dispatched to the widest one the running CPU supports, so the shuffle
+// loop gets auto-vectorized onto SIMD-width lanes instead of running scalar
+// everywhere; falls back to a plain scalar build when none of the listed
+// features are available.
+#[multiversion(targets(
+    "x86_64+avx2",
+    "x86_64+sse4.2",
+    "aarch64+neon",
+))]
+pub fn convert_bgra_to_rgb(data: Vec<u8>) -> Vec<u8> {
+    let mut rgb_data = vec![0u8; data.len() / 4 * 3];
+
+    for (src, dst) in data.chunks_exact(4).zip(rgb_data.chunks_exact_mut(3)) {
+        dst[0] = src[2];
+        dst[1] = src[1];
+        dst[2] = src[0];
+    }
+
+    rgb_data
+}
+
+// Converts tightly-packed BGRA to BGR by dropping the alpha channel, i.e.
+// compacting 4-byte groups down to 3-byte groups. Multiversioned for the
+// same reason as `convert_bgra_to_rgb`.
+#[multiversion(targets(
+    "x86_64+avx2",
+    "x86_64+sse4.2",
+    "aarch64+neon",
+))]
+pub fn remove_alpha_channel(data: Vec<u8>) -> Vec<u8> {
+    let mut bgr_data = vec![0u8; data.len() / 4 * 3];
+
+    for (src, dst) in data.chunks_exact(4).zip(bgr_data.chunks_exact_mut(3)) {
+        dst[0] = src[0];
+        dst[1] = src[1];
+        dst[2] = src[2];
+    }
+
+    bgr_data
+}